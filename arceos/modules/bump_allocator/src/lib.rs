@@ -1,9 +1,49 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 use allocator::{AllocError, AllocResult, BaseAllocator, ByteAllocator, PageAllocator};
 
 use core::alloc::Layout;
 use core::ptr::NonNull;
+
+const fn align_up(pos: usize, align: usize) -> usize {
+    (pos + align - 1) & !(align - 1)
+}
+
+const fn align_down(pos: usize, align: usize) -> usize {
+    pos & !(align - 1)
+}
+
+/// Cap on how many times the requested size [`EarlyAllocator::alloc_usable`]
+/// will report as usable slack, so a nearly-empty arena doesn't get reported
+/// as having effectively unbounded capacity for a tiny allocation.
+const USABLE_SLACK_MULTIPLIER: usize = 8;
+
+/// Highest order the buddy backend will split a region into: order *k*
+/// covers `2^k` pages, so `2^MAX_ORDER` pages is far beyond any pool this
+/// early allocator is realistically handed.
+const MAX_ORDER: usize = 32;
+
+/// Sentinel "no next block" value for the intrusive free-list links that
+/// live inside the free pages themselves.
+const BUDDY_NIL: usize = usize::MAX;
+
+const fn pages_for_order(order: usize) -> usize {
+    1usize << order
+}
+
+/// Safety: `addr` must be the base of a free block at least `size_of::<usize>()`
+/// bytes long that is not concurrently aliased.
+unsafe fn read_next(addr: usize) -> usize {
+    unsafe { *(addr as *const usize) }
+}
+
+/// Safety: same requirements as [`read_next`].
+unsafe fn write_next(addr: usize, next: usize) {
+    unsafe {
+        *(addr as *mut usize) = next;
+    }
+}
+
 /// Early memory allocator
 /// Use it before formal bytes-allocator and pages-allocator can work!
 /// This is a double-end memory range:
@@ -14,94 +54,816 @@ use core::ptr::NonNull;
 /// |            | -->    <-- |            |
 /// start       b_pos        p_pos       end
 ///
-/// For bytes area, 'count' records number of allocations.
+/// For bytes area, 'b_count' records number of allocations.
 /// When it goes down to ZERO, free bytes-used area.
-/// For pages area, it will never be freed!
+/// The start of the most recently handed-out byte block is also remembered,
+/// so a dealloc that matches it can roll `b_pos` straight back even while
+/// `b_count` is still above zero, turning the common LIFO pattern into real
+/// reuse instead of a pure bump.
+/// For pages area, it is never freed by default, but [`Self::enable_page_bitmap`]
+/// can switch it over to a reclaiming bitmap-backed mode: one bit per
+/// `PAGE_SIZE` frame, carved out of the avail-area, so that freed pages
+/// become reusable instead of being lost until the whole allocator is gone.
 ///
-pub struct EarlyAllocator<const PAGE_SIZE: usize> {
+/// Setting `BUDDY` hands the whole page-capable region over to a binary
+/// buddy allocator instead: `init` gives it the largest power-of-two run of
+/// pages that fits below `end`, and `alloc_pages`/`dealloc_pages` split and
+/// merge blocks within it, which cuts fragmentation for workloads that
+/// repeatedly allocate and free page runs of mixed sizes. Because the region
+/// is claimed up front, byte allocations only get whatever is left between
+/// the buddy's order table and the region's base, which may be nothing.
+///
+pub struct EarlyAllocator<const PAGE_SIZE: usize, const BUDDY: bool = false> {
     start: usize,
     b_pos: usize,
     p_pos: usize,
     end: usize,
+    b_count: usize,
+    last_b_alloc: Option<(usize, usize)>,
+    /// Lowest address the full-drain reset in [`ByteAllocator::dealloc`] may
+    /// roll `b_pos` back to. Normally `start`, but once
+    /// [`Self::enable_page_bitmap`] has carved the bitmap's backing storage
+    /// (or `init` the buddy order table) out of the byte arena, it is raised
+    /// past that storage so a later
+    /// drain-to-zero can't hand the bitmap's own bytes back out to a byte
+    /// allocation and corrupt it.
+    b_floor: usize,
+    /// Address of the page-frame bitmap, once [`Self::enable_page_bitmap`]
+    /// has carved it out of the avail-area. `None` means pages are still
+    /// handed out by the simple downward bump.
+    page_bitmap: Option<usize>,
+    page_bitmap_bytes: usize,
+    /// Address of the first frame tracked by `page_bitmap`.
+    page_bitmap_base: usize,
+    /// Number of `PAGE_SIZE` frames tracked by `page_bitmap`.
+    page_bitmap_frames: usize,
+    /// Order of the whole buddy-managed region; only meaningful when
+    /// `BUDDY` is `true`. The region's base is `p_pos`, which the buddy
+    /// backend claims once at `init` and never moves again.
+    buddy_order: usize,
+    /// Free-list heads, indexed by order. The links themselves are stored
+    /// intrusively inside the free pages, so this and `buddy_order_table`
+    /// are the only metadata the buddy backend needs outside the managed
+    /// region.
+    free_lists: [Option<usize>; MAX_ORDER + 1],
+    /// Address of a one-byte-per-frame table recording the order each
+    /// *allocated* block was actually handed out at (alignment can force a
+    /// larger block than `num_pages` alone implies), so `dealloc_pages` can
+    /// recover the real order instead of recomputing a possibly smaller one
+    /// from `num_pages`. Carved out of the avail-area just below the buddy
+    /// region at `init`; `None` if the region is empty.
+    buddy_order_table: Option<usize>,
 }
 
-impl<const PAGE_SIZE: usize> EarlyAllocator<PAGE_SIZE> {
+impl<const PAGE_SIZE: usize, const BUDDY: bool> EarlyAllocator<PAGE_SIZE, BUDDY> {
     pub const fn new() -> Self {
         Self {
             start: 0,
             b_pos: 0,
             p_pos: 0,
             end: 0,
+            b_count: 0,
+            last_b_alloc: None,
+            b_floor: 0,
+            page_bitmap: None,
+            page_bitmap_bytes: 0,
+            page_bitmap_base: 0,
+            page_bitmap_frames: 0,
+            buddy_order: 0,
+            free_lists: [None; MAX_ORDER + 1],
+            buddy_order_table: None,
         }
     }
+
+    /// Switches the page area from the simple downward bump to a reclaiming
+    /// bitmap: one bit per `PAGE_SIZE` frame between the current `b_pos` and
+    /// `end`. The bitmap itself is stored in the avail-area, just ahead of
+    /// `b_pos`, and frames already handed out by the bump path are carried
+    /// over as used. Safe to call more than once; later calls are no-ops.
+    pub fn enable_page_bitmap(&mut self) -> AllocResult {
+        if BUDDY {
+            // The buddy backend already owns and tracks the whole page
+            // region; the two reclaiming strategies are mutually exclusive.
+            return Err(AllocError::InvalidParam);
+        }
+        if self.page_bitmap.is_some() {
+            return Ok(());
+        }
+        let frames_upper_bound = (self.end - self.b_pos) / PAGE_SIZE;
+        let bitmap_bytes = frames_upper_bound.div_ceil(8).max(1);
+        let bitmap_addr = self.b_pos;
+        if bitmap_addr + bitmap_bytes > self.p_pos {
+            return Err(AllocError::NoMemory);
+        }
+        // Every frame starts out free.
+        unsafe {
+            core::ptr::write_bytes(bitmap_addr as *mut u8, 0, bitmap_bytes);
+        }
+        self.b_pos = bitmap_addr + bitmap_bytes;
+        // The bitmap's storage must never be handed back out to a byte
+        // allocation, so a later drain-to-zero can only roll back to here.
+        self.b_floor = self.b_pos;
+        // Nor may the last-block rollback reach back below it.
+        self.last_b_alloc = None;
+        let base = align_up(self.b_pos, PAGE_SIZE);
+        let frames = (self.end - base) / PAGE_SIZE;
+
+        self.page_bitmap = Some(bitmap_addr);
+        self.page_bitmap_bytes = bitmap_bytes;
+        self.page_bitmap_base = base;
+        self.page_bitmap_frames = frames;
+
+        // Pages already handed out by the bump path before the bitmap
+        // existed must be carried over as used.
+        let used_from = (self.p_pos.saturating_sub(base)) / PAGE_SIZE;
+        for frame in used_from..frames {
+            self.set_frame_used(frame, true);
+        }
+        Ok(())
+    }
+
+    /// Safety: only valid to call once `page_bitmap` is `Some`; the pointer
+    /// was carved out of the pool passed to `init` and stays valid for the
+    /// whole lifetime of `self`.
+    fn bitmap_byte(&self, byte: usize) -> *mut u8 {
+        unsafe { (self.page_bitmap.unwrap() as *mut u8).add(byte) }
+    }
+
+    fn frame_used(&self, frame: usize) -> bool {
+        let (byte, bit) = (frame / 8, frame % 8);
+        unsafe { *self.bitmap_byte(byte) & (1 << bit) != 0 }
+    }
+
+    fn set_frame_used(&self, frame: usize, used: bool) {
+        let (byte, bit) = (frame / 8, frame % 8);
+        unsafe {
+            let p = self.bitmap_byte(byte);
+            if used {
+                *p |= 1 << bit;
+            } else {
+                *p &= !(1 << bit);
+            }
+        }
+    }
+
+    fn alloc_pages_bitmap(&self, num_pages: usize, align_pow2: usize) -> AllocResult<usize> {
+        let frames = self.page_bitmap_frames;
+        if num_pages == 0 || num_pages > frames {
+            return Err(AllocError::NoMemory);
+        }
+        let mut frame = 0;
+        while frame + num_pages <= frames {
+            let base = self.page_bitmap_base + frame * PAGE_SIZE;
+            // Check alignment against the actual returned address, not just
+            // the frame index relative to `page_bitmap_base` - the base
+            // itself is only `PAGE_SIZE`-aligned, so a frame-relative check
+            // alone doesn't guarantee a stronger `align_pow2` is honored.
+            if base % align_pow2 != 0 {
+                frame += 1;
+                continue;
+            }
+            if (frame..frame + num_pages).all(|f| !self.frame_used(f)) {
+                for f in frame..frame + num_pages {
+                    self.set_frame_used(f, true);
+                }
+                return Ok(base);
+            }
+            frame += 1;
+        }
+        Err(AllocError::NoMemory)
+    }
+
+    fn dealloc_pages_bitmap(&self, pos: usize, num_pages: usize) {
+        if pos < self.page_bitmap_base {
+            return;
+        }
+        let frame = (pos - self.page_bitmap_base) / PAGE_SIZE;
+        for f in frame..(frame + num_pages).min(self.page_bitmap_frames) {
+            self.set_frame_used(f, false);
+        }
+    }
+
+    fn used_pages_bitmap(&self) -> usize {
+        let frames = self.page_bitmap_frames;
+        (0..frames).filter(|&f| self.frame_used(f)).count()
+    }
+
+    /// Upper bound for `b_pos`: once the page bitmap is enabled it owns a
+    /// fixed region starting at `page_bitmap_base`, so byte allocations may
+    /// no longer creep into it the way they could into the bump `p_pos`.
+    fn byte_limit(&self) -> usize {
+        if BUDDY {
+            return self.p_pos;
+        }
+        match self.page_bitmap {
+            Some(_) => self.page_bitmap_base,
+            None => self.p_pos,
+        }
+    }
+
+    /// Allocates `layout` like [`ByteAllocator::alloc`], but also reports how
+    /// many bytes are usable at the returned pointer before the next
+    /// obstacle, not just `layout.size()`. `alloc` always aligns `b_pos` up
+    /// first, so there is frequently slack between the end of a small,
+    /// over-aligned allocation and whatever comes next; a caller layered on
+    /// top (e.g. a growable buffer) can write into that slack and skip a
+    /// reallocation instead of letting it go to waste. The committed
+    /// `b_pos` only advances by `layout.size()`, same as `alloc` - the slack
+    /// is informational, valid for as long as no further allocation is made
+    /// in between.
+    pub fn alloc_usable(&mut self, layout: Layout) -> AllocResult<(NonNull<u8>, usize)> {
+        let size = layout.size();
+        let align = layout.align();
+        let align_mask = align - 1;
+        let new_pos = (self.b_pos + align_mask) & !align_mask;
+        let limit = self.byte_limit();
+        if new_pos + size > limit {
+            return Err(AllocError::NoMemory);
+        }
+        self.b_pos = new_pos + size;
+        self.b_count += 1;
+        self.last_b_alloc = Some((new_pos, size));
+
+        let usable = (limit - new_pos).min(size.saturating_mul(USABLE_SLACK_MULTIPLIER));
+        Ok((NonNull::new(new_pos as *mut u8).unwrap(), usable))
+    }
+
+    /// Claims the largest power-of-two run of pages that fits below `end`
+    /// (anchored at the top, just like the plain backward bump) and hands
+    /// the whole thing to the buddy free lists as a single block. Called
+    /// once from `init` when `BUDDY` is set; `p_pos` becomes the region's
+    /// fixed base and never moves again afterwards.
+    fn init_buddy(&mut self) {
+        self.free_lists = [None; MAX_ORDER + 1];
+        self.buddy_order_table = None;
+        let region_start = align_up(self.b_pos, PAGE_SIZE);
+        if self.p_pos <= region_start {
+            self.buddy_order = 0;
+            return;
+        }
+        // Reserve one order-tag byte per potential frame, carved from the
+        // bottom of the region, then fit the largest power-of-two run of
+        // pages into whatever is left above it.
+        let table_bytes = (self.p_pos - region_start) / PAGE_SIZE;
+        if table_bytes == 0 {
+            self.buddy_order = 0;
+            return;
+        }
+        let table_addr = region_start;
+        let page_area_start = align_up(table_addr + table_bytes, PAGE_SIZE);
+        if page_area_start >= self.p_pos {
+            self.buddy_order = 0;
+            return;
+        }
+        let avail_pages = (self.p_pos - page_area_start) / PAGE_SIZE;
+        if avail_pages == 0 {
+            self.buddy_order = 0;
+            return;
+        }
+        let order = (avail_pages.ilog2() as usize).min(MAX_ORDER);
+        let base = self.p_pos - pages_for_order(order) * PAGE_SIZE;
+
+        unsafe {
+            core::ptr::write_bytes(table_addr as *mut u8, 0, table_bytes);
+        }
+        self.buddy_order_table = Some(table_addr);
+        // Keep byte allocations (and a later drain-to-zero) from reaching
+        // back into the order table.
+        self.b_pos = table_addr + table_bytes;
+        self.b_floor = self.b_pos;
+        self.buddy_order = order;
+        self.p_pos = base;
+        self.buddy_push(order, base);
+    }
+
+    /// Safety: only valid once `buddy_order_table` is `Some`; `addr` must be
+    /// a frame base within the buddy region.
+    fn set_block_order(&self, addr: usize, order: usize) {
+        if let Some(table) = self.buddy_order_table {
+            let frame = (addr - self.p_pos) / PAGE_SIZE;
+            unsafe {
+                *(table as *mut u8).add(frame) = order as u8;
+            }
+        }
+    }
+
+    fn block_order(&self, addr: usize) -> usize {
+        match self.buddy_order_table {
+            Some(table) => {
+                let frame = (addr - self.p_pos) / PAGE_SIZE;
+                unsafe { *(table as *const u8).add(frame) as usize }
+            }
+            None => 0,
+        }
+    }
+
+    fn buddy_push(&mut self, order: usize, addr: usize) {
+        let next = self.free_lists[order].unwrap_or(BUDDY_NIL);
+        unsafe { write_next(addr, next) };
+        self.free_lists[order] = Some(addr);
+    }
+
+    fn buddy_pop(&mut self, order: usize) -> Option<usize> {
+        let head = self.free_lists[order]?;
+        let next = unsafe { read_next(head) };
+        self.free_lists[order] = (next != BUDDY_NIL).then_some(next);
+        Some(head)
+    }
+
+    fn buddy_remove(&mut self, order: usize, addr: usize) -> bool {
+        let mut prev = None;
+        let mut cur = self.free_lists[order];
+        while let Some(node) = cur {
+            let next = unsafe { read_next(node) };
+            let next = (next != BUDDY_NIL).then_some(next);
+            if node == addr {
+                match prev {
+                    Some(p) => unsafe { write_next(p, next.unwrap_or(BUDDY_NIL)) },
+                    None => self.free_lists[order] = next,
+                }
+                return true;
+            }
+            prev = Some(node);
+            cur = next;
+        }
+        false
+    }
+
+    fn buddy_available_pages(&self) -> usize {
+        let mut total = 0;
+        for order in 0..=self.buddy_order {
+            let mut cur = self.free_lists[order];
+            while let Some(addr) = cur {
+                total += pages_for_order(order);
+                let next = unsafe { read_next(addr) };
+                cur = (next != BUDDY_NIL).then_some(next);
+            }
+        }
+        total
+    }
+
+    fn alloc_pages_buddy(&mut self, num_pages: usize, align_pages: usize) -> AllocResult<usize> {
+        if num_pages == 0 {
+            return Err(AllocError::InvalidParam);
+        }
+        let order = num_pages.max(align_pages).next_power_of_two().trailing_zeros() as usize;
+        if order > self.buddy_order {
+            return Err(AllocError::NoMemory);
+        }
+        let Some(mut cur_order) = (order..=self.buddy_order).find(|&o| self.free_lists[o].is_some())
+        else {
+            return Err(AllocError::NoMemory);
+        };
+        let block = self.buddy_pop(cur_order).unwrap();
+        // Split the oversized block down to the requested order, pushing
+        // each unused buddy half back onto its own free list.
+        while cur_order > order {
+            cur_order -= 1;
+            let buddy = block + pages_for_order(cur_order) * PAGE_SIZE;
+            self.buddy_push(cur_order, buddy);
+        }
+        // Record the order this block was actually handed out at: alignment
+        // can force it larger than `num_pages` alone implies, and
+        // `dealloc_pages` has no alignment parameter to recompute it from.
+        self.set_block_order(block, order);
+        Ok(block)
+    }
+
+    fn dealloc_pages_buddy(&mut self, pos: usize, _num_pages: usize) {
+        let mut order = self.block_order(pos);
+        let mut addr = pos;
+        // Buddy blocks are naturally aligned to their own size, so the
+        // buddy of a block at `addr` is found by flipping the bit for that
+        // size in its offset from the region base.
+        while order < self.buddy_order {
+            let size = pages_for_order(order) * PAGE_SIZE;
+            let buddy = self.p_pos + ((addr - self.p_pos) ^ size);
+            if self.buddy_remove(order, buddy) {
+                addr = addr.min(buddy);
+                order += 1;
+            } else {
+                break;
+            }
+        }
+        self.buddy_push(order, addr);
+    }
 }
 
-impl<const PAGE_SIZE: usize> BaseAllocator for EarlyAllocator<PAGE_SIZE> {
+impl<const PAGE_SIZE: usize, const BUDDY: bool> BaseAllocator for EarlyAllocator<PAGE_SIZE, BUDDY> {
     fn init(&mut self, start: usize, size: usize) {
         self.start = start;
         self.end = start + size;
         self.b_pos = start;
         self.p_pos = self.end;
+        self.b_count = 0;
+        self.last_b_alloc = None;
+        self.b_floor = start;
+        self.page_bitmap = None;
+        self.page_bitmap_bytes = 0;
+        self.page_bitmap_base = 0;
+        self.page_bitmap_frames = 0;
+        self.buddy_order = 0;
+        self.free_lists = [None; MAX_ORDER + 1];
+        if BUDDY {
+            self.init_buddy();
+        }
     }
     fn add_memory(&mut self, _start: usize, _size: usize) -> AllocResult {
         Err(AllocError::NoMemory)
     }
 }
 
-impl<const PAGE_SIZE: usize> ByteAllocator for EarlyAllocator<PAGE_SIZE> {
+impl<const PAGE_SIZE: usize, const BUDDY: bool> ByteAllocator for EarlyAllocator<PAGE_SIZE, BUDDY> {
     fn alloc(&mut self, layout: Layout) -> AllocResult<NonNull<u8>> {
-        let size = layout.size();
-        let align = layout.align();
-        let align_mask = align - 1;
-        let new_pos = (self.b_pos + align_mask) & !align_mask;
-        if new_pos + size > self.p_pos {
-            return Err(AllocError::NoMemory);
-        }
-        self.b_pos = new_pos + size;
-        Ok(NonNull::new(new_pos as *mut u8).unwrap())
+        self.alloc_usable(layout).map(|(ptr, _usable)| ptr)
     }
-    fn dealloc(&mut self, _ptr: NonNull<u8>, _layout: Layout) {
-        // Do nothing
+    fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        if self.b_count == 0 {
+            return;
+        }
+        self.b_count -= 1;
+        if self.b_count == 0 {
+            // Last outstanding allocation went away: the whole forward arena
+            // is free, so reuse it from the start - or from `b_floor` if
+            // something (e.g. the page bitmap's backing storage) has
+            // permanently claimed a prefix of it.
+            self.b_pos = self.b_floor;
+            self.last_b_alloc = None;
+            return;
+        }
+        if self.last_b_alloc == Some((ptr.as_ptr() as usize, layout.size())) {
+            // This was the most recently handed-out block: roll `b_pos`
+            // straight back to it instead of leaving a hole behind.
+            self.b_pos = ptr.as_ptr() as usize;
+            self.last_b_alloc = None;
+        }
     }
     fn total_bytes(&self) -> usize {
         self.end - self.start
     }
     fn available_bytes(&self) -> usize {
-        self.p_pos - self.b_pos
+        self.byte_limit() - self.b_pos
     }
     fn used_bytes(&self) -> usize {
         self.b_pos - self.start
     }
 }
 
-impl<const PAGE_SIZE: usize> PageAllocator for EarlyAllocator<PAGE_SIZE> {
+impl<const PAGE_SIZE: usize, const BUDDY: bool> PageAllocator for EarlyAllocator<PAGE_SIZE, BUDDY> {
     const PAGE_SIZE: usize = PAGE_SIZE;
     fn alloc_pages(&mut self, num_pages: usize, align_pow2: usize) -> AllocResult<usize> {
         if align_pow2 % Self::PAGE_SIZE != 0 {
             return Err(AllocError::InvalidParam);
         }
-        let align_pow2 = align_pow2 / Self::PAGE_SIZE;
-        if !align_pow2.is_power_of_two() {
+        let align_frames = align_pow2 / Self::PAGE_SIZE;
+        if !align_frames.is_power_of_two() {
             return Err(AllocError::InvalidParam);
         }
-        let p_pos = self.p_pos - num_pages * Self::PAGE_SIZE;
-        if p_pos < self.b_pos {
+
+        if BUDDY {
+            return self.alloc_pages_buddy(num_pages, align_frames);
+        }
+
+        if self.page_bitmap.is_some() {
+            return self.alloc_pages_bitmap(num_pages, align_pow2);
+        }
+
+        // Round the candidate base *down* to the requested alignment rather
+        // than just subtracting the requested size, so `align_pow2` is
+        // actually honored instead of only ever landing on a `PAGE_SIZE`
+        // boundary. Whatever falls between the aligned base and the old
+        // `p_pos` is alignment padding; `p_pos` moving past it keeps
+        // `used_pages`/`available_pages` accounting for it automatically.
+        let candidate = self.p_pos - num_pages * Self::PAGE_SIZE;
+        let new_p = align_down(candidate, align_pow2);
+        if new_p < self.b_pos {
             return Err(AllocError::NoMemory);
         }
 
-        self.p_pos -= num_pages * Self::PAGE_SIZE;
-        Ok(self.p_pos)
+        self.p_pos = new_p;
+        Ok(new_p)
     }
-    fn dealloc_pages(&mut self, _pos: usize, _num_pages: usize) {
-        // Do nothing
+    fn dealloc_pages(&mut self, pos: usize, num_pages: usize) {
+        if BUDDY {
+            self.dealloc_pages_buddy(pos, num_pages);
+            return;
+        }
+        if self.page_bitmap.is_some() {
+            self.dealloc_pages_bitmap(pos, num_pages);
+        }
+        // The plain downward bump never reclaims pages.
     }
     fn total_pages(&self) -> usize {
         (self.end - self.start) / Self::PAGE_SIZE
     }
     fn used_pages(&self) -> usize {
+        if BUDDY {
+            return pages_for_order(self.buddy_order) - self.buddy_available_pages();
+        }
+        if self.page_bitmap.is_some() {
+            return self.used_pages_bitmap();
+        }
         (self.end - self.p_pos) / Self::PAGE_SIZE
     }
     fn available_pages(&self) -> usize {
+        if BUDDY {
+            return self.buddy_available_pages();
+        }
+        if self.page_bitmap.is_some() {
+            return self.page_bitmap_frames - self.used_pages_bitmap();
+        }
         self.p_pos / Self::PAGE_SIZE
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE_SIZE: usize = 0x1000;
+    const POOL_SIZE: usize = 0x10000;
+
+    fn new_allocator() -> (EarlyAllocator<PAGE_SIZE>, [u8; POOL_SIZE]) {
+        let pool = [0u8; POOL_SIZE];
+        let mut allocator = EarlyAllocator::<PAGE_SIZE>::new();
+        allocator.init(pool.as_ptr() as usize, POOL_SIZE);
+        (allocator, pool)
+    }
+
+    fn layout(size: usize, align: usize) -> Layout {
+        Layout::from_size_align(size, align).unwrap()
+    }
+
+    #[test]
+    fn interleaved_alloc_dealloc_reuses_last_block() {
+        let (mut a, _pool) = new_allocator();
+        let l = layout(64, 8);
+
+        let p1 = a.alloc(l).unwrap();
+        let pos_after_p1 = a.b_pos;
+        let p2 = a.alloc(l).unwrap();
+        assert_eq!(a.b_count, 2);
+
+        // Freeing the most recent block (p2) rolls b_pos back to it.
+        a.dealloc(p2, l);
+        assert_eq!(a.b_pos, pos_after_p1);
+        assert_eq!(a.b_count, 1);
+
+        // p1 is not the last allocation anymore once we allocate again,
+        // so freeing an earlier block leaves a hole instead of rewinding.
+        let p3 = a.alloc(l).unwrap();
+        assert_eq!(p3.as_ptr() as usize, pos_after_p1);
+        let pos_after_p3 = a.b_pos;
+        a.dealloc(p1, l);
+        assert_eq!(a.b_pos, pos_after_p3);
+        assert_eq!(a.b_count, 1);
+    }
+
+    #[test]
+    fn last_block_rollback_reuses_space_for_next_alloc() {
+        let (mut a, _pool) = new_allocator();
+        let l = layout(128, 8);
+
+        // Keep an earlier block live so b_count stays above zero and the
+        // dealloc below goes through the last-block rollback, not the reset.
+        let _keep = a.alloc(l).unwrap();
+        let p1 = a.alloc(l).unwrap();
+        let start = p1.as_ptr() as usize;
+        a.dealloc(p1, l);
+        assert_eq!(a.b_pos, start);
+        assert_eq!(a.b_count, 1);
+
+        // The freed space is immediately reusable.
+        let p2 = a.alloc(l).unwrap();
+        assert_eq!(p2.as_ptr() as usize, start);
+    }
+
+    #[test]
+    fn drain_to_zero_resets_bytes_area() {
+        let (mut a, _pool) = new_allocator();
+        let l = layout(32, 8);
+        let start = a.start;
+
+        let p1 = a.alloc(l).unwrap();
+        let p2 = a.alloc(l).unwrap();
+        let p3 = a.alloc(l).unwrap();
+        assert_eq!(a.b_count, 3);
+
+        // Free out of LIFO order; only the count reaching zero resets b_pos.
+        a.dealloc(p1, l);
+        assert_ne!(a.b_pos, start);
+        a.dealloc(p3, l);
+        a.dealloc(p2, l);
+        assert_eq!(a.b_count, 0);
+        assert_eq!(a.b_pos, start);
+        assert_eq!(a.used_bytes(), 0);
+    }
+
+    #[test]
+    fn bitmap_mode_reclaims_freed_pages() {
+        let (mut a, _pool) = new_allocator();
+        a.enable_page_bitmap().unwrap();
+
+        let p1 = a.alloc_pages(2, PAGE_SIZE).unwrap();
+        let p2 = a.alloc_pages(3, PAGE_SIZE).unwrap();
+        assert_eq!(a.used_pages(), 5);
+
+        a.dealloc_pages(p1, 2);
+        assert_eq!(a.used_pages(), 3);
+
+        // The freed frames are reused for a new allocation of the same size.
+        let p3 = a.alloc_pages(2, PAGE_SIZE).unwrap();
+        assert_eq!(p3, p1);
+        assert_eq!(a.used_pages(), 5);
+
+        a.dealloc_pages(p2, 3);
+        a.dealloc_pages(p3, 2);
+        assert_eq!(a.used_pages(), 0);
+        assert_eq!(a.available_pages(), a.page_bitmap_frames);
+    }
+
+    #[test]
+    fn bitmap_mode_caps_available_bytes_at_the_bitmap_boundary() {
+        let (mut a, _pool) = new_allocator();
+        a.enable_page_bitmap().unwrap();
+
+        // Once the bitmap is enabled, byte allocations can no longer creep
+        // past `page_bitmap_base`, so `available_bytes` must shrink to
+        // match instead of still reporting room out to the old `p_pos`.
+        assert_eq!(a.available_bytes(), a.page_bitmap_base - a.b_pos);
+
+        // The reported figure must actually be honored: consuming it all
+        // should leave zero bytes available and the next allocation should
+        // fail rather than creep into the bitmap.
+        let remaining = a.available_bytes();
+        let l = layout(remaining, 1);
+        a.alloc(l).unwrap();
+        assert_eq!(a.available_bytes(), 0);
+        assert!(a.alloc(layout(1, 1)).is_err());
+    }
+
+    #[test]
+    fn draining_bytes_to_zero_does_not_clobber_the_page_bitmap() {
+        let (mut a, _pool) = new_allocator();
+        a.enable_page_bitmap().unwrap();
+
+        let pages = a.alloc_pages(2, PAGE_SIZE).unwrap();
+
+        // An unrelated byte allocation drains to zero; the bitmap's own
+        // backing storage must survive the resulting full-arena reset.
+        let l = layout(32, 8);
+        let b = a.alloc(l).unwrap();
+        a.dealloc(b, l);
+        assert_eq!(a.b_count, 0);
+        assert!(a.b_pos >= a.b_floor);
+
+        // Reusing the freed byte arena must not overwrite the bitmap: the
+        // page allocated above should still read back as used.
+        let _b2 = a.alloc(layout(64, 8)).unwrap();
+        assert_eq!(a.used_pages(), 2);
+        a.dealloc_pages(pages, 2);
+        assert_eq!(a.used_pages(), 0);
+    }
+
+    #[test]
+    fn bitmap_mode_honors_stronger_than_page_size_alignment() {
+        // A pool large enough to carve out a non-trivial bitmap and still
+        // leave room for a 2 MiB-aligned run, with no guarantee that
+        // `page_bitmap_base` itself lands on a 2 MiB boundary.
+        const BIG_POOL: usize = 8 * 1024 * 1024;
+        const HUGE_ALIGN: usize = 2 * 1024 * 1024;
+        let pool = std::vec![0u8; BIG_POOL];
+        let mut a = EarlyAllocator::<PAGE_SIZE>::new();
+        a.init(pool.as_ptr() as usize, BIG_POOL);
+        a.enable_page_bitmap().unwrap();
+
+        let base = a.alloc_pages(HUGE_ALIGN / PAGE_SIZE, HUGE_ALIGN).unwrap();
+        assert_eq!(base % HUGE_ALIGN, 0);
+    }
+
+    #[test]
+    fn alloc_pages_honors_stronger_than_page_size_alignment() {
+        // A pool large enough that rounding a 2 MiB-aligned base down from
+        // the top still leaves room above `b_pos`.
+        const BIG_POOL: usize = 8 * 1024 * 1024;
+        const HUGE_ALIGN: usize = 2 * 1024 * 1024;
+        let pool = std::vec![0u8; BIG_POOL];
+        let mut a = EarlyAllocator::<PAGE_SIZE>::new();
+        a.init(pool.as_ptr() as usize, BIG_POOL);
+
+        let base = a.alloc_pages(HUGE_ALIGN / PAGE_SIZE, HUGE_ALIGN).unwrap();
+        assert_eq!(base % HUGE_ALIGN, 0);
+        assert!(base >= a.b_pos);
+
+        // A second huge-page region must also land on its own 2 MiB
+        // boundary, below the first one.
+        let base2 = a.alloc_pages(HUGE_ALIGN / PAGE_SIZE, HUGE_ALIGN).unwrap();
+        assert_eq!(base2 % HUGE_ALIGN, 0);
+        assert!(base2 + HUGE_ALIGN <= base);
+    }
+
+    #[test]
+    fn bitmap_mode_carries_over_pages_allocated_before_it_was_enabled() {
+        let (mut a, _pool) = new_allocator();
+        let legacy = a.alloc_pages(4, PAGE_SIZE).unwrap();
+        a.enable_page_bitmap().unwrap();
+
+        // The bump-allocated region must show up as used in the bitmap so a
+        // fresh allocation can't be handed out on top of it.
+        assert!(a.used_pages() >= 4);
+        // The scan starts at the lowest tracked frame, so the fresh
+        // allocation lands below the legacy one rather than overlapping it.
+        let fresh = a.alloc_pages(1, PAGE_SIZE).unwrap();
+        assert!(fresh + PAGE_SIZE <= legacy);
+    }
+
+    fn new_buddy_allocator() -> (EarlyAllocator<PAGE_SIZE, true>, [u8; POOL_SIZE]) {
+        let pool = [0u8; POOL_SIZE];
+        let mut allocator = EarlyAllocator::<PAGE_SIZE, true>::new();
+        allocator.init(pool.as_ptr() as usize, POOL_SIZE);
+        (allocator, pool)
+    }
+
+    #[test]
+    fn buddy_alloc_pages_are_naturally_aligned_and_non_overlapping() {
+        let (mut a, _pool) = new_buddy_allocator();
+
+        let p1 = a.alloc_pages(1, PAGE_SIZE).unwrap();
+        let p2 = a.alloc_pages(4, PAGE_SIZE).unwrap();
+        // A 4-page block's buddy-assigned base is naturally aligned to its
+        // own size *within the managed region*, satisfying a 4-page
+        // alignment request for free.
+        assert_eq!((p2 - a.p_pos) % (4 * PAGE_SIZE), 0);
+        assert!(p1 + PAGE_SIZE <= p2 || p2 + 4 * PAGE_SIZE <= p1);
+    }
+
+    #[test]
+    fn buddy_dealloc_merges_back_into_a_single_block() {
+        let (mut a, _pool) = new_buddy_allocator();
+        let total = a.available_pages();
+
+        let p1 = a.alloc_pages(2, PAGE_SIZE).unwrap();
+        let p2 = a.alloc_pages(2, PAGE_SIZE).unwrap();
+        assert_eq!(a.available_pages(), total - 4);
+
+        a.dealloc_pages(p1, 2);
+        a.dealloc_pages(p2, 2);
+        // Freeing both buddy halves should merge them back up, restoring
+        // the full block to a single top-level free list entry.
+        assert_eq!(a.available_pages(), total);
+        assert_eq!(a.free_lists[a.buddy_order], Some(a.p_pos));
+    }
+
+    #[test]
+    fn buddy_dealloc_reclaims_the_whole_block_when_alignment_forced_it_larger() {
+        let (mut a, _pool) = new_buddy_allocator();
+        let total = a.available_pages();
+
+        // Alignment forces a real 4-page block even though only 1 page was
+        // requested; the freed block must give back all 4, not just 1.
+        let p = a.alloc_pages(1, 4 * PAGE_SIZE).unwrap();
+        assert_eq!(a.available_pages(), total - 4);
+
+        a.dealloc_pages(p, 1);
+        assert_eq!(a.available_pages(), total);
+    }
+
+    #[test]
+    fn buddy_mode_reports_available_pages_as_sum_over_orders() {
+        let (mut a, _pool) = new_buddy_allocator();
+        let total = a.available_pages();
+
+        let _p1 = a.alloc_pages(1, PAGE_SIZE).unwrap();
+        let _p2 = a.alloc_pages(2, PAGE_SIZE).unwrap();
+        assert_eq!(a.available_pages(), total - 3);
+        assert_eq!(a.used_pages(), 3);
+    }
+
+    #[test]
+    fn alloc_usable_reports_slack_without_overlapping_the_page_region() {
+        let (mut a, _pool) = new_allocator();
+        let (ptr, usable) = a.alloc_usable(layout(4, 64)).unwrap();
+
+        assert!(usable >= 4);
+        assert!(ptr.as_ptr() as usize + usable <= a.byte_limit());
+        // `alloc_usable` only ever committed the requested size, so the very next
+        // allocation still starts right after it.
+        assert_eq!(a.b_pos, ptr.as_ptr() as usize + 4);
+    }
+
+    #[test]
+    fn alloc_usable_is_capped_relative_to_the_request() {
+        let (mut a, _pool) = new_allocator();
+        let (_, usable) = a.alloc_usable(layout(8, 8)).unwrap();
+        assert!(usable <= 8 * USABLE_SLACK_MULTIPLIER);
+    }
+
+    #[test]
+    fn alloc_is_a_thin_wrapper_over_alloc_usable() {
+        let (mut a, _pool) = new_allocator();
+        let l = layout(16, 8);
+        let ptr = a.alloc(l).unwrap();
+        assert_eq!(a.b_pos, ptr.as_ptr() as usize + 16);
+        assert_eq!(a.b_count, 1);
+    }
+}